@@ -1,11 +1,11 @@
 #![forbid(unsafe_code)]
-#![feature(const_generics, const_evaluatable_checked, const_fn)]
+#![feature(generic_const_exprs)]
 #![allow(incomplete_features)]
-#![feature(const_panic)] // TODO dbg
 
 use core::cmp;
 use std::borrow::BorrowMut;
-use std::ops::{Add, Index, IndexMut};
+use std::marker::PhantomData;
+use std::ops::{Add, Index, IndexMut, Range};
 
 // Order starts from one and counts up the tree
 // Level starts from one and counts down the tree
@@ -14,7 +14,8 @@ use std::ops::{Add, Index, IndexMut};
 
 mod flat_tree {
     pub const fn sibling_of(index: usize) -> usize {
-        if index & 1 == 0 {
+        // Left children sit at odd indices (2i + 1), right children at even (2i + 2).
+        if index & 1 == 1 {
             index + 1
         } else {
             index - 1
@@ -32,28 +33,51 @@ mod flat_tree {
     pub const fn blocks_in_level(level: u8) -> usize {
         blocks_in_tree(level) - blocks_in_tree(level - 1)
     }
+
+    pub const fn left_child_of(index: usize) -> usize {
+        2 * index + 1
+    }
+
+    pub const fn right_child_of(index: usize) -> usize {
+        2 * index + 2
+    }
 }
 
 mod nested_tree {
     use crate::flat_tree::blocks_in_tree as blocks_in_flat_tree;
     pub const LEVELS_IN_SUBTREE: u8 = 6; // 2 ^ 6 - 1 = 63 ~= cache line on x86_64
-    pub const SIZE_OF_SUBTREE: usize = 1 << (LEVELS_IN_SUBTREE - 1);
 
+    // Packing a tree into cache-line-sized subtrees only changes where each node
+    // lives, not how many of them there are, so this always agrees with the flat
+    // tree's count.
     pub const fn blocks_in_tree(levels_in_tree: u8) -> usize {
-        let perfect_flat_levels_in_subtrees =
-            (levels_in_tree / LEVELS_IN_SUBTREE) * LEVELS_IN_SUBTREE;
-        let leftover_blocks = blocks_in_flat_tree(levels_in_tree)
-            - blocks_in_flat_tree(perfect_flat_levels_in_subtrees);
-        let blocks_in_subtrees =
-            perfect_flat_levels_in_subtrees as usize * blocks_in_flat_tree(LEVELS_IN_SUBTREE);
-
-        blocks_in_subtrees + leftover_blocks
+        blocks_in_flat_tree(levels_in_tree)
     }
 }
 
-use crate::nested_tree::{LEVELS_IN_SUBTREE, SIZE_OF_SUBTREE};
+use crate::nested_tree::LEVELS_IN_SUBTREE;
 pub use nested_tree::blocks_in_tree;
 
+/// A monoid over node summaries. `Tree` stores one `Summary` per node and folds a
+/// node's two children into its own summary with `op`, so any associative range
+/// query (max, min, sum, gcd, ...) can share the same nested, cache-oblivious layout.
+trait Op {
+    type Summary: Copy;
+    type Value;
+
+    /// The summary of an empty range; `op(identity(), s) == s` for all `s`.
+    fn identity() -> Self::Summary;
+
+    /// Summarize a single leaf value.
+    fn summarize(value: &Self::Value) -> Self::Summary;
+
+    /// Combine the summaries of two sibling subtrees into their parent's summary.
+    /// `child_order` is the natural order of each of `left`/`right` (i.e. the order
+    /// at which either summary, taken alone, would describe a single fully-summarized
+    /// subtree), for `Op`s whose merge rule depends on more than the two summaries.
+    fn op(left: Self::Summary, right: Self::Summary, child_order: u8) -> Self::Summary;
+}
+
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
 #[repr(transparent)]
 struct Block {
@@ -77,7 +101,39 @@ impl Add<u8> for Block {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The buddy-allocator invariant ("greatest free order below me") expressed as an
+/// [`Op`]: two equal, fully-free children coalesce into one order higher, otherwise
+/// the parent is as free as its freest child.
+struct BuddyOp;
+
+impl Op for BuddyOp {
+    type Summary = Block;
+    type Value = bool;
+
+    fn identity() -> Block {
+        Block { order_free: 0 }
+    }
+
+    fn summarize(value: &bool) -> Block {
+        Block {
+            order_free: if *value { 1 } else { 0 },
+        }
+    }
+
+    fn op(left: Block, right: Block, child_order: u8) -> Block {
+        // Only coalesce when both children are themselves *fully* free, i.e. each is
+        // one single free block of its own natural order — matching `order_free` on
+        // either side to `child_order` is what distinguishes that from two children
+        // that merely report the same best-available free order somewhere below them.
+        if left.order_free == child_order && right.order_free == child_order {
+            left + 1
+        } else {
+            cmp::max(left, right)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[repr(transparent)]
 struct GlobalIndex(pub usize);
 
@@ -103,146 +159,146 @@ struct Order(pub u8);
 #[repr(transparent)]
 struct SubtreeOrder(pub u8);
 
-struct Tree<B, const LEVELS: u8> {
+struct Tree<B, const LEVELS: u8, O> {
     blocks: B,
+    op: PhantomData<O>,
 }
 
-impl<B, const LEVELS: u8> Index<GlobalIndex> for Tree<B, LEVELS>
+impl<B, const LEVELS: u8, O: Op> Index<GlobalIndex> for Tree<B, LEVELS, O>
 where
-    B: BorrowMut<[Block; blocks_in_tree(LEVELS)]>,
+    B: BorrowMut<[O::Summary; blocks_in_tree(LEVELS)]>,
 {
-    type Output = Block;
+    type Output = O::Summary;
 
-    fn index(&self, index: GlobalIndex) -> &Block {
+    fn index(&self, index: GlobalIndex) -> &O::Summary {
         &self.blocks.borrow()[index.0]
     }
 }
 
-impl<B, const LEVELS: u8> IndexMut<GlobalIndex> for Tree<B, LEVELS>
+impl<B, const LEVELS: u8, O: Op> IndexMut<GlobalIndex> for Tree<B, LEVELS, O>
 where
-    B: BorrowMut<[Block; blocks_in_tree(LEVELS)]>,
+    B: BorrowMut<[O::Summary; blocks_in_tree(LEVELS)]>,
 {
-    fn index_mut(&mut self, index: GlobalIndex) -> &mut Block {
+    fn index_mut(&mut self, index: GlobalIndex) -> &mut O::Summary {
         &mut self.blocks.borrow_mut()[index.0]
     }
 }
 
-impl<B, const LEVELS: u8> Tree<B, LEVELS>
+impl<B, const LEVELS: u8> Tree<B, LEVELS, BuddyOp>
 where
     B: BorrowMut<[Block; blocks_in_tree(LEVELS)]>,
 {
     fn new_free(mut blocks: B) -> Self {
-        let mut borrowed_blocks = blocks.borrow_mut();
-
-        let slice_offset = Self::subtree_slice_offset(SubtreeOrder(0));
-        let tree_offset_size = if LEVELS % LEVELS_IN_SUBTREE != 0 {
-            (1 << ((LEVELS % LEVELS_IN_SUBTREE) - 1) - 1)
-        } else {
-            1 << (LEVELS_IN_SUBTREE - 2)
-        };
-        let bottom_level_size = borrowed_blocks.len() - slice_offset;
-
-        for tree in 0.. {
-            let tree_offset = tree_offset_size * tree;
-            if tree_offset > borrowed_blocks.len() {
-                break;
-            }
+        let total_leaves = 1usize << (LEVELS - 1);
 
-            for i in tree_offset..bottom_level_size {
-                borrowed_blocks[slice_offset + i] = Block { order_free: 1 };
+        {
+            let borrowed_blocks = blocks.borrow_mut();
+            for leaf in 0..total_leaves {
+                borrowed_blocks[Self::leaf_global_idx(leaf)] = BuddyOp::summarize(&true);
             }
         }
 
-        let mut tree = Tree { blocks };
-
-        for j in 0.. {
-            let tree_offset = tree_offset_size * j;
-            if tree_offset > tree.blocks.borrow().len() {
-                break;
-            }
+        let mut tree = Tree {
+            blocks,
+            op: PhantomData,
+        };
 
-            for i in tree_offset..bottom_level_size {
-                tree.update_blocks_above(GlobalIndex(slice_offset + i), Order(1));
-            }
+        for leaf in 0..total_leaves {
+            tree.update_blocks_above(GlobalIndex(Self::leaf_global_idx(leaf)), Order(1));
         }
 
         tree
     }
+}
 
-    /// Update all the parents of a block
+impl<B, const LEVELS: u8, O: Op> Tree<B, LEVELS, O>
+where
+    B: BorrowMut<[O::Summary; blocks_in_tree(LEVELS)]>,
+{
+    /// Update all the parents of a block of natural order `order.0`.
     fn update_blocks_above(&mut self, mut index: GlobalIndex, order: Order) {
-        // Iterate upwards and set parents accordingly
-        for order in (order.0 - 1)..LEVELS {
-            let order = Order(order);
-            let block = self[index];
-            let sibling = self[Self::sibling(index, order)];
-            let parent_idx = Self::parent(index, order);
-            dbg!((order.0, index, parent_idx));
-
-            // Set the parent appropriately and carry on propagating upwards
-            self.merge_from_children(parent_idx, block, sibling);
+        let mut current_order = order.0;
+
+        while current_order < LEVELS {
+            let summary = self[index];
+            let sibling = self[Self::sibling(index, Order(current_order))];
+            let parent_idx = Self::parent(index, Order(current_order));
+
+            self[parent_idx] = O::op(summary, sibling, current_order);
             index = parent_idx;
+            current_order += 1;
         }
     }
 
+    /// Which packed subtree *layer* a node of `order` belongs to: layer 0 holds the
+    /// leaves (and every node up to `LEVELS_IN_SUBTREE`), layer 1 the next
+    /// `LEVELS_IN_SUBTREE` orders up, and so on, with the topmost layer absorbing
+    /// whatever's left over when `LEVELS` isn't an exact multiple of
+    /// `LEVELS_IN_SUBTREE`.
     const fn subtree_order(order: Order) -> SubtreeOrder {
-        SubtreeOrder(if order.0 <= LEVELS % LEVELS_IN_SUBTREE {
-            0
-        } else {
-            (order.0 - (LEVELS % LEVELS_IN_SUBTREE)) / LEVELS_IN_SUBTREE + 1
-        })
+        SubtreeOrder((order.0 - 1) / LEVELS_IN_SUBTREE)
     }
 
-    // TODO const
-    fn parent(idx: GlobalIndex, order: Order) -> GlobalIndex {
-        let subtree_order = Self::subtree_order(order);
-        let local_idx = Self::local_idx(idx, subtree_order);
-        let subtree_idx = Self::subtree_idx(idx, subtree_order);
-        dbg!(subtree_order);
-
-        if local_idx.is_root() {
-            println!("root: {:?}", local_idx);
-            let parent_subtree_order = SubtreeOrder(subtree_order.0 + 1);
-            let subtree_slice_offset = Self::subtree_slice_offset(subtree_order);
-            let subtree_slice_idx = idx.0 - subtree_slice_offset;
-            let parent_subtree_idx = SubtreeIndex(subtree_slice_idx << 1);
+    const fn topmost_subtree_order() -> SubtreeOrder {
+        Self::subtree_order(Order(LEVELS))
+    }
 
-            Self::global_idx(LocalIndex(0), parent_subtree_idx, parent_subtree_order)
+    /// How many tree-levels `subtree_order`'s layer spans: a full `LEVELS_IN_SUBTREE`
+    /// for every layer except the topmost, which only gets the remainder.
+    const fn subtree_order_levels(subtree_order: SubtreeOrder) -> u8 {
+        if subtree_order.0 == Self::topmost_subtree_order().0 {
+            LEVELS - subtree_order.0 * LEVELS_IN_SUBTREE
         } else {
-            println!("Local");
-            let parent_local = LocalIndex(flat_tree::parent_of(local_idx.0));
-            Self::global_idx(parent_local, subtree_idx, subtree_order)
+            LEVELS_IN_SUBTREE
         }
     }
 
-    fn sibling(idx: GlobalIndex, order: Order) -> GlobalIndex {
-        let subtree_order = Self::subtree_order(order);
-        let subtree_idx = Self::subtree_idx(idx, subtree_order);
-        let local_idx = Self::local_idx(idx, subtree_order);
-
-        if local_idx.is_root() {
-            let sibling_subtree_idx = SubtreeIndex(flat_tree::sibling_of(subtree_idx.0));
-            Self::global_idx(LocalIndex(0), sibling_subtree_idx, subtree_order)
+    /// How many copies of `subtree_order`'s layer are packed side by side: the
+    /// topmost layer is a single instance holding the root, and every layer below
+    /// it has one instance per child of the leaves of the layer above, i.e.
+    /// `2^(LEVELS - LEVELS_IN_SUBTREE * (subtree_order + 1))` instances.
+    fn layer_instances(subtree_order: SubtreeOrder) -> usize {
+        if subtree_order.0 == Self::topmost_subtree_order().0 {
+            1
         } else {
-            let sibling_local = LocalIndex(flat_tree::sibling_of(local_idx.0));
-            Self::global_idx(sibling_local, subtree_idx, subtree_order)
+            1 << (LEVELS - LEVELS_IN_SUBTREE * (subtree_order.0 + 1))
         }
     }
 
+    fn size_of_subtree(subtree_order: SubtreeOrder) -> usize {
+        blocks_in_tree(Self::subtree_order_levels(subtree_order))
+    }
+
+    /// Array offset where `subtree_order`'s layer starts. The topmost layer (which
+    /// contains the root) sits at offset zero, and every layer below it is packed
+    /// after all the layers above it, so that `GlobalIndex(0)` is always the root.
     fn subtree_slice_offset(subtree_order: SubtreeOrder) -> usize {
-        if subtree_order.0 < LEVELS - 2 {
-            blocks_in_tree(LEVELS - subtree_order.0 - 1)
-        } else {
-            0
+        let mut order = Self::topmost_subtree_order().0;
+        let mut offset = 0;
+
+        while order > subtree_order.0 {
+            let this_order = SubtreeOrder(order);
+            offset += Self::layer_instances(this_order) * Self::size_of_subtree(this_order);
+            order -= 1;
         }
+
+        offset
     }
 
-    fn size_of_subtree(subtree_order: SubtreeOrder) -> usize {
-        if subtree_order.0 == 0 && LEVELS % LEVELS_IN_SUBTREE != 0 {
-            (1 << (LEVELS % LEVELS_IN_SUBTREE) - 1)
-        } else {
-            SIZE_OF_SUBTREE
-        }
+    /// Global index of the `leaf`-th leaf (0-indexed, left to right) among this
+    /// tree's `2^(LEVELS - 1)` leaves, spread evenly across layer 0's packed
+    /// subtree instances.
+    fn leaf_global_idx(leaf: usize) -> usize {
+        let leaf_subtree_order = SubtreeOrder(0);
+        let leaf_layer_offset = Self::subtree_slice_offset(leaf_subtree_order);
+        let leaf_layer_levels = Self::subtree_order_levels(leaf_subtree_order);
+        let instance_size = Self::size_of_subtree(leaf_subtree_order);
+        let leaf_row_start = blocks_in_tree(leaf_layer_levels - 1);
+        let leaves_per_instance = 1usize << (leaf_layer_levels - 1);
+
+        let instance = leaf / leaves_per_instance;
+        let local_leaf = leaf % leaves_per_instance;
+        leaf_layer_offset + instance * instance_size + leaf_row_start + local_leaf
     }
 
     fn subtree_idx(idx: GlobalIndex, subtree_order: SubtreeOrder) -> SubtreeIndex {
@@ -266,12 +322,596 @@ where
         GlobalIndex(subtree_idx.0 * Self::size_of_subtree(subtree_order) + local_idx.0 + subtree_slice_offset)
     }
 
-    fn merge_from_children(&mut self, idx: GlobalIndex, left: Block, right: Block) {
-        self[idx] = if left == right && !left.is_used() {
-            left + 1
+    fn parent(idx: GlobalIndex, order: Order) -> GlobalIndex {
+        let subtree_order = Self::subtree_order(order);
+        let local_idx = Self::local_idx(idx, subtree_order);
+        let subtree_idx = Self::subtree_idx(idx, subtree_order);
+
+        if !local_idx.is_root() {
+            let parent_local = LocalIndex(flat_tree::parent_of(local_idx.0));
+            return Self::global_idx(parent_local, subtree_idx, subtree_order);
+        }
+
+        // `idx` is the root of its packed subtree; its parent lives one layer up,
+        // attached to one of that layer's own leaves.
+        let parent_order = SubtreeOrder(subtree_order.0 + 1);
+        let attach_slot = subtree_idx.0 / 2;
+
+        let leaves_per_parent = 1 << (Self::subtree_order_levels(parent_order) - 1);
+        let parent_subtree_idx = SubtreeIndex(attach_slot / leaves_per_parent);
+        let local_leaf = attach_slot % leaves_per_parent;
+        let leaf_row_start = blocks_in_tree(Self::subtree_order_levels(parent_order) - 1);
+
+        Self::global_idx(
+            LocalIndex(leaf_row_start + local_leaf),
+            parent_subtree_idx,
+            parent_order,
+        )
+    }
+
+    fn sibling(idx: GlobalIndex, order: Order) -> GlobalIndex {
+        let subtree_order = Self::subtree_order(order);
+        let local_idx = Self::local_idx(idx, subtree_order);
+        let subtree_idx = Self::subtree_idx(idx, subtree_order);
+
+        if !local_idx.is_root() {
+            let sibling_local = LocalIndex(flat_tree::sibling_of(local_idx.0));
+            return Self::global_idx(sibling_local, subtree_idx, subtree_order);
+        }
+
+        // `idx` is the root of its packed subtree, so its buddy is the root of the
+        // other subtree sharing the same attachment point one layer up. Subtree
+        // instances pair up by index (0,1), (2,3), ... regardless of the layer.
+        let sibling_subtree_idx = SubtreeIndex(subtree_idx.0 ^ 1);
+        Self::global_idx(LocalIndex(0), sibling_subtree_idx, subtree_order)
+    }
+
+    /// The two children of the block at `idx` (natural order `order.0`). Inverse of
+    /// [`Tree::parent`]: for either returned child `c`,
+    /// `Self::parent(c, Order(order.0 - 1)) == idx`.
+    fn children(idx: GlobalIndex, order: Order) -> (GlobalIndex, GlobalIndex) {
+        let subtree_order = Self::subtree_order(order);
+        let local_idx = Self::local_idx(idx, subtree_order);
+        let subtree_idx = Self::subtree_idx(idx, subtree_order);
+
+        Self::children_of_decomposed(local_idx, subtree_idx, subtree_order)
+    }
+
+    /// [`Tree::children`], already decomposed into its packed-subtree coordinates;
+    /// shared with [`Cursor::children`] so it only has to cache the offset, not
+    /// duplicate this math.
+    fn children_of_decomposed(
+        local_idx: LocalIndex,
+        subtree_idx: SubtreeIndex,
+        subtree_order: SubtreeOrder,
+    ) -> (GlobalIndex, GlobalIndex) {
+        let left_local = flat_tree::left_child_of(local_idx.0);
+
+        if left_local < Self::size_of_subtree(subtree_order) {
+            let right_local = flat_tree::right_child_of(local_idx.0);
+
+            (
+                Self::global_idx(LocalIndex(left_local), subtree_idx, subtree_order),
+                Self::global_idx(LocalIndex(right_local), subtree_idx, subtree_order),
+            )
         } else {
-            cmp::max(left, right)
+            // `local_idx` is a leaf of this packed subtree; its children are the
+            // roots of the two subtrees attached one layer down.
+            let leaf_row_start = blocks_in_tree(Self::subtree_order_levels(subtree_order) - 1);
+            let local_leaf = local_idx.0 - leaf_row_start;
+            let leaves_per_instance = 1 << (Self::subtree_order_levels(subtree_order) - 1);
+            let attach_slot = subtree_idx.0 * leaves_per_instance + local_leaf;
+
+            let child_subtree_order = SubtreeOrder(subtree_order.0 - 1);
+            let left_child_idx = SubtreeIndex(2 * attach_slot);
+            let right_child_idx = SubtreeIndex(2 * attach_slot + 1);
+
+            (
+                Self::global_idx(LocalIndex(0), left_child_idx, child_subtree_order),
+                Self::global_idx(LocalIndex(0), right_child_idx, child_subtree_order),
+            )
+        }
+    }
+
+    /// Answer an associative range query over the leaves `[range.start, range.end)`.
+    ///
+    /// Walks the nested subtree layout top-down via [`Tree::children`], short-circuiting
+    /// into a cached node's summary as soon as its leaf span lies entirely inside
+    /// `range`, and only recursing into subtrees that straddle the query boundary.
+    fn fold(&self, range: Range<usize>) -> O::Summary {
+        self.fold_from(GlobalIndex(0), LEVELS, 0, &range)
+    }
+
+    fn fold_from(
+        &self,
+        idx: GlobalIndex,
+        order: u8,
+        leaf_start: usize,
+        range: &Range<usize>,
+    ) -> O::Summary {
+        let span = 1usize << (order - 1);
+        let leaf_end = leaf_start + span;
+
+        if range.end <= leaf_start || leaf_end <= range.start {
+            return O::identity();
+        }
+
+        if range.start <= leaf_start && leaf_end <= range.end {
+            return self[idx];
+        }
+
+        let (left, right) = Self::children(idx, Order(order));
+        let mid = leaf_start + span / 2;
+
+        O::op(
+            self.fold_from(left, order - 1, leaf_start, range),
+            self.fold_from(right, order - 1, mid, range),
+            order - 1,
+        )
+    }
+}
+
+impl<B, const LEVELS: u8> Tree<B, LEVELS, BuddyOp>
+where
+    B: BorrowMut<[Block; blocks_in_tree(LEVELS)]>,
+    [(); LEVELS as usize]: ,
+{
+    /// Reserve a free block of the given `order`, returning its index.
+    ///
+    /// Descends from the root, at each step picking a child whose `order_free` can
+    /// satisfy `order` (preferring the left/lower-address child when both qualify),
+    /// until reaching the level that matches `order`. Returns `None` if the root
+    /// itself has no block free enough to satisfy the request.
+    fn allocate(&mut self, order: Order) -> Option<GlobalIndex> {
+        if self[GlobalIndex(0)].order_free < order.0 {
+            return None;
+        }
+
+        let mut index = GlobalIndex(0);
+        let mut current_order = LEVELS;
+
+        while current_order > order.0 {
+            let (left, right) = Self::children(index, Order(current_order));
+
+            index = if self[left].order_free >= order.0 {
+                left
+            } else {
+                right
+            };
+
+            current_order -= 1;
+        }
+
+        self[index] = Block { order_free: 0 };
+        self.update_blocks_above(index, order);
+
+        Some(index)
+    }
+
+    /// Release a block of the given `order` previously returned by [`Tree::allocate`],
+    /// restoring its natural free order and coalescing it with its buddy if that is
+    /// also free.
+    fn free(&mut self, index: GlobalIndex, order: Order) {
+        self[index] = Block { order_free: order.0 };
+        self.update_blocks_above(index, order);
+    }
+
+    /// A cursor over this tree's free blocks, starting above the root.
+    fn cursor(&self) -> Cursor<'_, B, LEVELS> {
+        Cursor {
+            tree: self,
+            stack: [(GlobalIndex(0), Order(0)); LEVELS as usize],
+            depth: 0,
+            cached_subtree: None,
+        }
+    }
+
+    /// Permanently exclude the leaf range `[start, start + len)` from allocation,
+    /// e.g. an MMIO hole or a firmware-reserved region that doesn't align to a
+    /// single power-of-two block.
+    ///
+    /// Blocks that straddle the boundary are split by descending to the exact
+    /// split point; blocks fully inside the range are marked used all the way
+    /// down to the leaves, so that a later range which only partly covers such a
+    /// block recomputes it from children that are already used. The invariant is
+    /// then restored bottom-up along the boundary paths, the same merge
+    /// [`Tree::update_blocks_above`] performs. Safe to call with overlapping or
+    /// repeated ranges.
+    fn reserve_range(&mut self, start: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+
+        self.reserve_from(GlobalIndex(0), LEVELS, 0, &(start..start + len));
+    }
+
+    fn reserve_from(
+        &mut self,
+        idx: GlobalIndex,
+        order: u8,
+        leaf_start: usize,
+        range: &Range<usize>,
+    ) -> Block {
+        let span = 1usize << (order - 1);
+        let leaf_end = leaf_start + span;
+
+        if range.end <= leaf_start || leaf_end <= range.start {
+            return self[idx];
+        }
+
+        if range.start <= leaf_start && leaf_end <= range.end {
+            self.mark_used(idx, order);
+            return self[idx];
+        }
+
+        let (left, right) = Self::children(idx, Order(order));
+        let mid = leaf_start + span / 2;
+
+        let left_summary = self.reserve_from(left, order - 1, leaf_start, range);
+        let right_summary = self.reserve_from(right, order - 1, mid, range);
+
+        self[idx] = BuddyOp::op(left_summary, right_summary, order - 1);
+        self[idx]
+    }
+
+    /// Mark the block at `idx` (natural `order`) and every block below it as used.
+    fn mark_used(&mut self, idx: GlobalIndex, order: u8) {
+        self[idx] = Block { order_free: 0 };
+
+        if order > 1 {
+            let (left, right) = Self::children(idx, Order(order));
+            self.mark_used(left, order - 1);
+            self.mark_used(right, order - 1);
+        }
+    }
+}
+
+/// A cursor that seeks to and iterates over a `Tree`'s free blocks without
+/// recomputing index math from scratch at every step.
+///
+/// It keeps the path from the root to its current position as a small,
+/// fixed-capacity stack (depth is bounded by `LEVELS`, so no heap allocation is
+/// needed), and caches the base offset and subtree size of the packed-subtree
+/// layer it last stepped through, so that further steps within that layer skip
+/// `subtree_slice_offset`/`size_of_subtree`.
+struct Cursor<'a, B, const LEVELS: u8>
+where
+    B: BorrowMut<[Block; blocks_in_tree(LEVELS)]>,
+    [(); LEVELS as usize]: ,
+{
+    tree: &'a Tree<B, LEVELS, BuddyOp>,
+    // `(index, order)` pairs from the root down to the cursor's current position.
+    stack: [(GlobalIndex, Order); LEVELS as usize],
+    depth: usize,
+    // `(layer, slice offset, subtree size)` of the last layer stepped through.
+    cached_subtree: Option<(SubtreeOrder, usize, usize)>,
+}
+
+impl<'a, B, const LEVELS: u8> Cursor<'a, B, LEVELS>
+where
+    B: BorrowMut<[Block; blocks_in_tree(LEVELS)]>,
+    [(); LEVELS as usize]: ,
+{
+    fn push(&mut self, index: GlobalIndex, order: Order) {
+        self.stack[self.depth] = (index, order);
+        self.depth += 1;
+    }
+
+    /// The children of `idx` (natural order `order.0`, same convention as
+    /// [`Tree::children`]), reusing the cached slice offset and subtree size when
+    /// `idx` lies in the same packed-subtree layer as the last lookup.
+    fn children(&mut self, idx: GlobalIndex, order: Order) -> (GlobalIndex, GlobalIndex) {
+        let subtree_order = Tree::<B, LEVELS, BuddyOp>::subtree_order(order);
+
+        let (slice_offset, size) = match self.cached_subtree {
+            Some((cached_order, offset, size)) if cached_order.0 == subtree_order.0 => (offset, size),
+            _ => {
+                let offset = Tree::<B, LEVELS, BuddyOp>::subtree_slice_offset(subtree_order);
+                let size = Tree::<B, LEVELS, BuddyOp>::size_of_subtree(subtree_order);
+                self.cached_subtree = Some((subtree_order, offset, size));
+                (offset, size)
+            }
         };
+
+        let subtree_slice_idx = idx.0 - slice_offset;
+        let subtree_idx = SubtreeIndex(subtree_slice_idx / size);
+        let local_idx = LocalIndex(subtree_slice_idx % size);
+
+        Tree::<B, LEVELS, BuddyOp>::children_of_decomposed(local_idx, subtree_idx, subtree_order)
+    }
+
+    /// Descend from the current top of the stack to the lowest-address free block of
+    /// `order`, pushing the chosen child (preferring left) at every step.
+    fn descend_to(&mut self, order: Order) -> Option<GlobalIndex> {
+        loop {
+            let (index, current_order) = self.stack[self.depth - 1];
+
+            if current_order.0 == order.0 {
+                return Some(index);
+            }
+
+            let (left, right) = self.children(index, current_order);
+            let child_order = Order(current_order.0 - 1);
+
+            if self.tree[left].order_free >= order.0 {
+                self.push(left, child_order);
+            } else if self.tree[right].order_free >= order.0 {
+                self.push(right, child_order);
+            } else {
+                return None;
+            }
+        }
+    }
+
+    /// Seek to the lowest-address free block of the given `order`, resetting the
+    /// cursor to start its search from the root.
+    fn seek_free(&mut self, order: Order) -> Option<GlobalIndex> {
+        self.depth = 0;
+        self.cached_subtree = None;
+
+        if self.tree[GlobalIndex(0)].order_free < order.0 {
+            return None;
+        }
+
+        self.push(GlobalIndex(0), Order(LEVELS));
+        self.descend_to(order)
+    }
+
+    /// Advance to the next free block of `order` after the one last returned by
+    /// [`Cursor::seek_free`]/[`Cursor::next_free`], in ascending address order.
+    /// Returns `None` once there are no more qualifying blocks.
+    fn next_free(&mut self, order: Order) -> Option<GlobalIndex> {
+        // Pop back up the stack looking for the nearest ancestor we reached via its
+        // left child whose right subtree also qualifies.
+        while self.depth > 1 {
+            let (index, _) = self.stack[self.depth - 1];
+            let (parent_index, parent_order) = self.stack[self.depth - 2];
+            self.depth -= 1;
+
+            let (left, right) = self.children(parent_index, parent_order);
+
+            if index == left && self.tree[right].order_free >= order.0 {
+                self.push(right, Order(parent_order.0 - 1));
+                return self.descend_to(order);
+            }
+        }
+
+        None
+    }
+}
+
+/// Number of `u64` words needed to hold one bit per block of `order` in a tree of
+/// `levels` levels.
+const fn bitmap_words_at_order(levels: u8, order: u8) -> usize {
+    let blocks = 1usize << (levels - order);
+    blocks.div_ceil(64)
+}
+
+/// Total words needed across every order's bitmap, `1..=levels`.
+const fn bitmap_words(levels: u8) -> usize {
+    let mut order = 1;
+    let mut total = 0;
+
+    while order <= levels {
+        total += bitmap_words_at_order(levels, order);
+        order += 1;
+    }
+
+    total
+}
+
+/// The word offset at which `order`'s bitmap starts.
+const fn bitmap_word_offset(levels: u8, order: u8) -> usize {
+    let mut o = 1;
+    let mut offset = 0;
+
+    while o < order {
+        offset += bitmap_words_at_order(levels, o);
+        o += 1;
+    }
+
+    offset
+}
+
+/// An alternative, denser backing for the buddy allocator: instead of one `Block`
+/// byte per tree node, this keeps one *free-list bitmap per order*, packed into
+/// `u64` words. Finding a free block of a given order is then a `trailing_zeros`
+/// scan over that order's words, with no pointer-chasing through the tree, at the
+/// cost of giving up [`Tree::fold`]'s general range queries. Implements
+/// [`BuddyAllocator`] alongside [`Tree`], so callers pick this or `Tree` for their
+/// storage behind the same `allocate`/`free` methods.
+struct BitmapTree<W, const LEVELS: u8> {
+    words: W,
+}
+
+impl<W, const LEVELS: u8> BitmapTree<W, LEVELS>
+where
+    W: BorrowMut<[u64; bitmap_words(LEVELS)]>,
+{
+    fn new_free(mut words: W) -> Self {
+        for word in words.borrow_mut().iter_mut() {
+            *word = 0;
+        }
+
+        let mut tree = BitmapTree { words };
+
+        for block in 0..(1usize << (LEVELS - 1)) {
+            tree.set_free(Order(1), block, true);
+        }
+
+        for order in 2..=LEVELS {
+            for block in 0..(1usize << (LEVELS - order)) {
+                let both_free =
+                    tree.is_free(Order(order - 1), 2 * block) && tree.is_free(Order(order - 1), 2 * block + 1);
+                tree.set_free(Order(order), block, both_free);
+            }
+        }
+
+        tree
+    }
+
+    fn bit_location(order: Order, block: usize) -> (usize, u32) {
+        let offset = bitmap_word_offset(LEVELS, order.0);
+        (offset + block / 64, (block % 64) as u32)
+    }
+
+    fn is_free(&self, order: Order, block: usize) -> bool {
+        let (word, bit) = Self::bit_location(order, block);
+        self.words.borrow()[word] & (1u64 << bit) != 0
+    }
+
+    fn set_free(&mut self, order: Order, block: usize, free: bool) {
+        let (word, bit) = Self::bit_location(order, block);
+
+        if free {
+            self.words.borrow_mut()[word] |= 1u64 << bit;
+        } else {
+            self.words.borrow_mut()[word] &= !(1u64 << bit);
+        }
+    }
+
+    /// The lowest-address free block of exactly `order`, found by scanning its
+    /// bitmap a word at a time instead of walking the tree.
+    fn find_free(&self, order: Order) -> Option<usize> {
+        let offset = bitmap_word_offset(LEVELS, order.0);
+        let words = bitmap_words_at_order(LEVELS, order.0);
+
+        self.words.borrow()[offset..offset + words]
+            .iter()
+            .enumerate()
+            .find(|(_, word)| **word != 0)
+            .map(|(i, word)| i * 64 + word.trailing_zeros() as usize)
+    }
+
+    /// Reserve the lowest-address free block of `order`, returning its index within
+    /// that order's bitmap.
+    fn allocate(&mut self, order: Order) -> Option<usize> {
+        let block = self.find_free(order)?;
+
+        // `block` being free at `order` means (by the same invariant `Tree` relies
+        // on) every finer block underneath it is currently free too; take all of
+        // them out of circulation along with `block` itself, not just `block`'s own
+        // bit, or a later allocation at a finer order would find free-looking space
+        // that's actually already handed out as part of this one.
+        self.invalidate_descendants(order, block);
+        self.set_free(order, block, false);
+        self.invalidate_ancestors(order, block);
+
+        Some(block)
+    }
+
+    /// Clear every ancestor bit that was only set because `block` (and its buddies,
+    /// recursively) used to be fully free.
+    fn invalidate_ancestors(&mut self, mut order: Order, mut block: usize) {
+        while order.0 < LEVELS {
+            block /= 2;
+            order = Order(order.0 + 1);
+
+            if !self.is_free(order, block) {
+                break;
+            }
+
+            self.set_free(order, block, false);
+        }
+    }
+
+    /// Clear the bits of every block at a finer order than `order` that falls
+    /// under `block`'s span, since allocating `block` takes them out of
+    /// circulation too.
+    fn invalidate_descendants(&mut self, order: Order, block: usize) {
+        let mut finer_order = order.0;
+        let mut span = 1usize;
+
+        while finer_order > 1 {
+            finer_order -= 1;
+            span *= 2;
+
+            for descendant in block * span..(block + 1) * span {
+                self.set_free(Order(finer_order), descendant, false);
+            }
+        }
+    }
+
+    /// Release the block at `block` (order `order`), coalescing with its buddy and
+    /// propagating the merge upward for as long as both children of an ancestor are
+    /// free.
+    fn free(&mut self, order: Order, block: usize) {
+        self.set_free(order, block, true);
+        self.restore_descendants(order, block);
+
+        let mut order = order;
+        let mut block = block;
+
+        while order.0 < LEVELS {
+            let buddy = block ^ 1;
+            if !self.is_free(order, buddy) {
+                break;
+            }
+
+            block /= 2;
+            order = Order(order.0 + 1);
+            self.set_free(order, block, true);
+        }
+    }
+
+    /// Set the bits of every block at a finer order than `order` that falls under
+    /// `block`'s span back to free, undoing [`BitmapTree::invalidate_descendants`].
+    fn restore_descendants(&mut self, order: Order, block: usize) {
+        let mut finer_order = order.0;
+        let mut span = 1usize;
+
+        while finer_order > 1 {
+            finer_order -= 1;
+            span *= 2;
+
+            for descendant in block * span..(block + 1) * span {
+                self.set_free(Order(finer_order), descendant, true);
+            }
+        }
+    }
+}
+
+/// Common interface over a buddy allocator backing, so callers can pick [`Tree`]
+/// (supports [`Tree::fold`]'s general range queries) or [`BitmapTree`] (denser,
+/// word-parallel free-block scan) for their storage without rewriting call sites.
+trait BuddyAllocator {
+    /// A handle to a reserved block, returned by `allocate` and later passed back
+    /// to `free`.
+    type Handle;
+
+    fn allocate(&mut self, order: Order) -> Option<Self::Handle>;
+
+    fn free(&mut self, handle: Self::Handle, order: Order);
+}
+
+impl<B, const LEVELS: u8> BuddyAllocator for Tree<B, LEVELS, BuddyOp>
+where
+    B: BorrowMut<[Block; blocks_in_tree(LEVELS)]>,
+    [(); LEVELS as usize]: ,
+{
+    type Handle = GlobalIndex;
+
+    fn allocate(&mut self, order: Order) -> Option<GlobalIndex> {
+        Tree::allocate(self, order)
+    }
+
+    fn free(&mut self, handle: GlobalIndex, order: Order) {
+        Tree::free(self, handle, order)
+    }
+}
+
+impl<W, const LEVELS: u8> BuddyAllocator for BitmapTree<W, LEVELS>
+where
+    W: BorrowMut<[u64; bitmap_words(LEVELS)]>,
+{
+    type Handle = usize;
+
+    fn allocate(&mut self, order: Order) -> Option<usize> {
+        BitmapTree::allocate(self, order)
+    }
+
+    fn free(&mut self, handle: usize, order: Order) {
+        BitmapTree::free(self, order, handle)
     }
 }
 
@@ -311,18 +951,247 @@ mod tests {
 
     #[test]
     fn test_init_tree() {
-        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8>;
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
         let tree = TestTree::new_free(new_flat_blocks());
 
-        // Highest level has 1 block, next has 2, next 4
+        // Highest level has 1 block, next has 2; the four order-6 blocks are the
+        // roots of layer 0's packed subtree instances, not a contiguous row, since
+        // each instance also carries its own interior nodes.
         assert_eq!(tree.blocks[0].order_free, 8);
 
         assert_eq!(tree.blocks[1].order_free, 7);
         assert_eq!(tree.blocks[2].order_free, 7);
 
-        assert_eq!(tree.blocks[3].order_free, 6);
-        assert_eq!(tree.blocks[4].order_free, 6);
-        assert_eq!(tree.blocks[5].order_free, 6);
-        assert_eq!(tree.blocks[6].order_free, 6);
+        let layer0_offset = TestTree::subtree_slice_offset(SubtreeOrder(0));
+        let layer0_size = TestTree::size_of_subtree(SubtreeOrder(0));
+
+        for instance in 0..4 {
+            assert_eq!(
+                tree.blocks[layer0_offset + instance * layer0_size].order_free,
+                6
+            );
+        }
+    }
+
+    #[test]
+    fn test_allocate_and_free_updates_ancestors() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let mut tree = TestTree::new_free(new_flat_blocks());
+
+        let allocated = tree.allocate(Order(1)).expect("root should have free space");
+        assert!(tree[allocated].is_used());
+        assert!(tree.blocks[0].order_free < 8);
+
+        tree.free(allocated, Order(1));
+
+        // Freeing should restore the pristine, fully-coalesced tree from `test_init_tree`.
+        assert_eq!(tree.blocks[0].order_free, 8);
+        assert_eq!(tree.blocks[1].order_free, 7);
+        assert_eq!(tree.blocks[2].order_free, 7);
+    }
+
+    #[test]
+    fn test_allocate_fails_when_root_too_small() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let mut tree = TestTree::new_free(new_flat_blocks());
+
+        assert!(tree.allocate(Order(9)).is_none());
+    }
+
+    #[test]
+    fn test_fold_full_range_matches_root() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let tree = TestTree::new_free(new_flat_blocks());
+
+        let leaves = 1usize << 7; // 2 ^ (LEVELS - 1)
+        assert_eq!(tree.fold(0..leaves), tree[GlobalIndex(0)]);
+    }
+
+    /// A second `Op`, unrelated to the buddy allocator, proving `Tree`/`fold` work for
+    /// any associative monoid over the same nested layout.
+    struct MaxOp;
+
+    impl Op for MaxOp {
+        type Summary = u8;
+        type Value = u8;
+
+        fn identity() -> u8 {
+            0
+        }
+
+        fn summarize(value: &u8) -> u8 {
+            *value
+        }
+
+        fn op(left: u8, right: u8, _child_order: u8) -> u8 {
+            cmp::max(left, right)
+        }
+    }
+
+    #[test]
+    fn test_fold_generic_max_op() {
+        type MaxTree = Tree<Box<[u8; blocks_in_tree(8)]>, 8, MaxOp>;
+
+        let leaf_count = 1usize << 7; // 2 ^ (LEVELS - 1)
+
+        let mut blocks: Box<[u8; blocks_in_tree(8)]> = Box::new([0; blocks_in_tree(8)]);
+        blocks[MaxTree::leaf_global_idx(5)] = MaxOp::summarize(&9);
+        blocks[MaxTree::leaf_global_idx(40)] = MaxOp::summarize(&4);
+
+        let mut tree = MaxTree {
+            blocks,
+            op: PhantomData,
+        };
+
+        for leaf in 0..leaf_count {
+            tree.update_blocks_above(GlobalIndex(MaxTree::leaf_global_idx(leaf)), Order(1));
+        }
+
+        assert_eq!(tree.fold(0..leaf_count), 9);
+        assert_eq!(tree.fold(0..10), 9);
+        assert_eq!(tree.fold(10..leaf_count), 4);
+    }
+
+    #[test]
+    fn test_cursor_iterates_all_free_leaves_in_order() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let tree = TestTree::new_free(new_flat_blocks());
+
+        let leaf_count = 1usize << 7; // 2 ^ (LEVELS - 1)
+
+        let mut cursor = tree.cursor();
+        let mut found = Vec::new();
+        let mut next = cursor.seek_free(Order(1));
+
+        while let Some(index) = next {
+            found.push(index.0);
+            next = cursor.next_free(Order(1));
+        }
+
+        let expected: Vec<usize> = (0..leaf_count).map(TestTree::leaf_global_idx).collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_cursor_seek_free_fails_above_root_order() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let tree = TestTree::new_free(new_flat_blocks());
+
+        let mut cursor = tree.cursor();
+        assert!(cursor.seek_free(Order(9)).is_none());
+    }
+
+    fn new_empty_words<const N: usize>() -> Box<[u64; N]> {
+        Box::new([0; N])
+    }
+
+    #[test]
+    fn test_bitmap_tree_init() {
+        type TestBitmapTree = BitmapTree<Box<[u64; bitmap_words(4)]>, 4>;
+        let tree = TestBitmapTree::new_free(new_empty_words());
+
+        // A fresh tree coalesces all the way to the root.
+        assert!(tree.is_free(Order(4), 0));
+        assert!(tree.is_free(Order(3), 0));
+        assert!(tree.is_free(Order(3), 1));
+        assert!(tree.is_free(Order(1), 0));
+        assert!(tree.is_free(Order(1), 7));
+    }
+
+    #[test]
+    fn test_bitmap_tree_allocate_and_free() {
+        type TestBitmapTree = BitmapTree<Box<[u64; bitmap_words(4)]>, 4>;
+        let mut tree = TestBitmapTree::new_free(new_empty_words());
+
+        let block = tree.allocate(Order(1)).expect("root should have free space");
+        assert_eq!(block, 0);
+        assert!(!tree.is_free(Order(1), 0));
+
+        // Allocating the first leaf splits every ancestor up to the root.
+        assert!(!tree.is_free(Order(2), 0));
+        assert!(!tree.is_free(Order(3), 0));
+        assert!(!tree.is_free(Order(4), 0));
+
+        // The buddy's path is untouched.
+        assert!(tree.is_free(Order(1), 1));
+
+        tree.free(Order(1), block);
+
+        // Freeing should restore the pristine, fully-coalesced tree.
+        assert!(tree.is_free(Order(4), 0));
+        assert!(tree.is_free(Order(3), 0));
+        assert!(tree.is_free(Order(1), 0));
+    }
+
+    #[test]
+    fn test_bitmap_tree_coarse_allocation_blocks_finer_allocations_inside_it() {
+        type TestBitmapTree = BitmapTree<Box<[u64; bitmap_words(3)]>, 3>;
+        let mut tree = TestBitmapTree::new_free(new_empty_words());
+
+        // Take the whole tree as one order-3 block.
+        tree.allocate(Order(3)).expect("root should have free space");
+
+        // Every finer block underneath it is no longer independently available:
+        // `allocate` cleared their bits along with the coarse block's own.
+        assert!(tree.allocate(Order(2)).is_none());
+        assert!(tree.allocate(Order(1)).is_none());
+
+        tree.free(Order(3), 0);
+
+        // Freeing the coarse block restores availability at every finer order too.
+        assert!(tree.allocate(Order(1)).is_some());
+    }
+
+    /// Round-trips a single `order`-sized allocation through the [`BuddyAllocator`]
+    /// trait rather than either backing's inherent methods, so it's exercised
+    /// generically over whichever backing the caller picks.
+    fn roundtrip_via_buddy_allocator<A: BuddyAllocator>(allocator: &mut A, order: Order) {
+        let handle = allocator
+            .allocate(order)
+            .expect("fresh allocator should have free space");
+        allocator.free(handle, order);
+    }
+
+    #[test]
+    fn test_buddy_allocator_trait_covers_both_backings() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let mut tree = TestTree::new_free(new_flat_blocks());
+        roundtrip_via_buddy_allocator(&mut tree, Order(1));
+
+        type TestBitmapTree = BitmapTree<Box<[u64; bitmap_words(4)]>, 4>;
+        let mut bitmap_tree = TestBitmapTree::new_free(new_empty_words());
+        roundtrip_via_buddy_allocator(&mut bitmap_tree, Order(1));
+    }
+
+    #[test]
+    fn test_reserve_range_marks_straddling_and_interior_blocks_used() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let mut tree = TestTree::new_free(new_flat_blocks());
+
+        // Reserve a range that straddles block boundaries rather than lining up
+        // with a single power-of-two block.
+        tree.reserve_range(3, 10);
+
+        // The root can no longer satisfy an allocation as large as it used to.
+        assert!(tree[GlobalIndex(0)].order_free < 8);
+
+        // There's still free space outside the reserved range to allocate from.
+        assert!(tree.allocate(Order(1)).is_some());
+    }
+
+    #[test]
+    fn test_reserve_range_is_idempotent_for_overlapping_ranges() {
+        type TestTree = Tree<Box<[Block; blocks_in_tree(8)]>, 8, BuddyOp>;
+        let mut tree = TestTree::new_free(new_flat_blocks());
+
+        tree.reserve_range(10, 20);
+        let order_free_after_first = tree[GlobalIndex(0)].order_free;
+
+        // Reserving an overlapping range, then the first range again, must not make
+        // any of the first range free again, so the root is no freer than before.
+        tree.reserve_range(15, 30);
+        tree.reserve_range(10, 20);
+
+        assert!(tree[GlobalIndex(0)].order_free <= order_free_after_first);
     }
 }